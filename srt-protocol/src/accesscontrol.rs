@@ -0,0 +1,189 @@
+//! StreamID parsing for `StreamAcceptor`.
+
+/// The `m=` value of a parsed [`StreamId`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamIdMode {
+    Request,
+    Publish,
+    Bidirectional,
+}
+
+/// A StreamID parsed per the SRT access-control convention: a `#!::`-prefixed
+/// string of comma-separated `key=value` pairs using the well-known keys
+/// `u` (user), `r` (resource), `h` (host), `s` (session id), `t` (type), and
+/// `m` (mode). Unrecognized keys are ignored.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StreamId {
+    pub user: Option<String>,
+    pub resource: Option<String>,
+    pub host: Option<String>,
+    pub session_id: Option<String>,
+    pub kind: Option<String>,
+    pub mode: Option<StreamIdMode>,
+}
+
+/// The raw StreamID did not conform to the `#!::key=value,...` convention.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum StreamIdError {
+    #[error("invalid escape sequence in StreamID: {0}")]
+    InvalidEscape(String),
+    #[error("unrecognized StreamID mode: {0}")]
+    InvalidMode(String),
+}
+
+const STREAMID_SENTINEL: &str = "#!::";
+
+impl StreamId {
+    /// Parses a raw StreamID. Strings that don't start with the `#!::`
+    /// sentinel aren't the structured convention and are left to the
+    /// caller to interpret as an opaque/raw id.
+    pub fn parse(raw: &str) -> Result<Option<StreamId>, StreamIdError> {
+        let Some(rest) = raw.strip_prefix(STREAMID_SENTINEL) else {
+            return Ok(None);
+        };
+
+        let mut sid = StreamId::default();
+        for pair in split_unescaped(rest) {
+            let (key, value) = match pair.split_once('=') {
+                Some(kv) => kv,
+                None => continue,
+            };
+            let value = unescape(value)?;
+            match key {
+                "u" => sid.user = Some(value),
+                "r" => sid.resource = Some(value),
+                "h" => sid.host = Some(value),
+                "s" => sid.session_id = Some(value),
+                "t" => sid.kind = Some(value),
+                "m" => {
+                    sid.mode = Some(match value.as_str() {
+                        "request" => StreamIdMode::Request,
+                        "publish" => StreamIdMode::Publish,
+                        "bidirectional" => StreamIdMode::Bidirectional,
+                        _ => return Err(StreamIdError::InvalidMode(value)),
+                    })
+                }
+                _ => {}
+            }
+        }
+        Ok(Some(sid))
+    }
+}
+
+/// Splits `s` on commas not escaped with a leading backslash, leaving each
+/// piece's escapes for `unescape` to resolve. Must run before `unescape`,
+/// not after - `\,` is the mechanism for embedding a literal comma.
+fn split_unescaped(s: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut chars = s.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            ',' => {
+                parts.push(&s[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+/// Undoes the StreamID escaping rules: `%` introduces a two-hex-digit byte
+/// escape, and `\,`/`\\` escape a literal comma/backslash.
+fn unescape(value: &str) -> Result<String, StreamIdError> {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '%' => {
+                let hex: String = chars.by_ref().take(2).collect();
+                if hex.len() != 2 {
+                    return Err(StreamIdError::InvalidEscape(value.to_string()));
+                }
+                let byte = u8::from_str_radix(&hex, 16)
+                    .map_err(|_| StreamIdError::InvalidEscape(value.to_string()))?;
+                // non-ASCII bytes are one piece of a multi-byte UTF-8
+                // sequence escaped byte-by-byte; decoding one alone as
+                // `byte as char` would mangle it, so reject instead.
+                if !byte.is_ascii() {
+                    return Err(StreamIdError::InvalidEscape(value.to_string()));
+                }
+                out.push(byte as char);
+            }
+            '\\' => match chars.next() {
+                Some(next @ (',' | '\\')) => out.push(next),
+                _ => return Err(StreamIdError::InvalidEscape(value.to_string())),
+            },
+            other => out.push(other),
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod streamid_tests {
+    use super::*;
+
+    #[test]
+    fn parses_well_known_keys() {
+        let sid = StreamId::parse("#!::u=alice,r=movie,m=publish")
+            .unwrap()
+            .unwrap();
+        assert_eq!(sid.user.as_deref(), Some("alice"));
+        assert_eq!(sid.resource.as_deref(), Some("movie"));
+        assert_eq!(sid.mode, Some(StreamIdMode::Publish));
+    }
+
+    #[test]
+    fn non_conforming_strings_are_left_raw() {
+        assert_eq!(StreamId::parse("just-a-raw-id").unwrap(), None);
+    }
+
+    #[test]
+    fn escaped_comma_does_not_split_the_value() {
+        let sid = StreamId::parse("#!::r=movie\\,part2,m=publish")
+            .unwrap()
+            .unwrap();
+        assert_eq!(sid.resource.as_deref(), Some("movie,part2"));
+        assert_eq!(sid.mode, Some(StreamIdMode::Publish));
+    }
+
+    #[test]
+    fn escaped_backslash_round_trips() {
+        let sid = StreamId::parse("#!::r=a\\\\b").unwrap().unwrap();
+        assert_eq!(sid.resource.as_deref(), Some("a\\b"));
+    }
+
+    #[test]
+    fn percent_escape_decodes_hex_byte() {
+        let sid = StreamId::parse("#!::r=a%2cb").unwrap().unwrap();
+        assert_eq!(sid.resource.as_deref(), Some("a,b"));
+    }
+
+    #[test]
+    fn truncated_percent_escape_is_rejected() {
+        assert!(StreamId::parse("#!::r=a%2").is_err());
+    }
+
+    #[test]
+    fn non_ascii_percent_escape_is_rejected_rather_than_mangled() {
+        // `%e2%82%ac` is `€` escaped byte-by-byte; decoding each byte alone
+        // as a codepoint would produce garbage instead of `€`.
+        assert!(StreamId::parse("#!::r=%e2%82%ac").is_err());
+    }
+
+    #[test]
+    fn dangling_backslash_is_rejected() {
+        assert!(StreamId::parse("#!::r=a\\").is_err());
+    }
+
+    #[test]
+    fn invalid_mode_is_rejected() {
+        assert!(StreamId::parse("#!::m=bogus").is_err());
+    }
+}
@@ -2,11 +2,12 @@
 
 use super::{ConnInitSettings, ConnectError, ConnectionReject};
 use crate::{
-    accesscontrol::StreamAcceptor,
+    accesscontrol::{StreamAcceptor, StreamId},
     crypto::CryptoManager,
     packet::{
-        HSV5Info, HandshakeControlInfo, HandshakeVSInfo, ServerRejectReason, SrtControlPacket,
-        SrtHandshake, SrtShakeFlags,
+        CoreRejectReason, GroupId, GroupRequest, GroupResponse, GroupType, HSV5Info,
+        HandshakeControlInfo, HandshakeVSInfo, ServerRejectReason, SrtControlPacket, SrtHandshake,
+        SrtShakeFlags,
     },
     ConnectionSettings, SrtVersion,
 };
@@ -15,31 +16,365 @@ use std::{
     time::{Duration, Instant},
 };
 
+mod group;
+pub use group::{GroupDedup, GroupManager, GroupMember, MemberHealth};
+
+/// Drives in-stream key regeneration once a connection is established.
+/// `CryptoManager` tags packets with the active key's KK bit and counts
+/// packets sent under it; this turns "time to rotate" into the KMREQ/KMRSP
+/// exchange on the wire. The caller is responsible for calling `poll` from
+/// the data-send loop and routing mid-connection KM control packets to
+/// `on_kmrsp` - this module only ever sees the handshake's own KM exchange.
+#[derive(Debug, Clone, Default)]
+pub struct KmRefresher {
+    pending: bool,
+}
+
+impl KmRefresher {
+    pub fn new() -> Self {
+        KmRefresher::default()
+    }
+
+    /// Generates a fresh SEK into `cm`'s spare slot and returns the KMREQ to
+    /// send, once the pre-announce margin before `cm`'s refresh period is
+    /// reached. Returns `None` if a refresh is already in flight or not due.
+    pub fn poll(&mut self, cm: &mut CryptoManager) -> Option<SrtControlPacket> {
+        if !Self::should_announce(
+            self.pending,
+            cm.packets_encrypted(),
+            cm.refresh_period(),
+            cm.pre_announce(),
+        ) {
+            return None;
+        }
+        self.pending = true;
+        Some(SrtControlPacket::KeyManagerRequest(cm.begin_refresh()))
+    }
+
+    /// Handles a KMRSP for a rekey in flight (the initial handshake KMRSP
+    /// is validated separately, in `finish_hsv5_initiation`). Once the peer
+    /// acks, new packets tag with the refreshed key while old packets still
+    /// in flight keep decoding under the previous one until they drain.
+    pub fn on_kmrsp(
+        &mut self,
+        cm: &mut CryptoManager,
+        response: &SrtControlPacket,
+    ) -> Result<(), ConnectError> {
+        match response {
+            SrtControlPacket::KeyManagerResponse(kmrsp) => {
+                cm.validate_km(kmrsp)?;
+                cm.commit_refresh();
+                self.pending = false;
+                Ok(())
+            }
+            _ => Err(ConnectError::ExpectedKMResp),
+        }
+    }
+
+    fn should_announce(
+        pending: bool,
+        packets_since_refresh: u64,
+        refresh_period: u64,
+        pre_announce: u64,
+    ) -> bool {
+        !pending && packets_since_refresh + pre_announce >= refresh_period
+    }
+}
+
+#[cfg(test)]
+mod km_refresher_tests {
+    use super::*;
+
+    #[test]
+    fn not_due_before_the_pre_announce_margin() {
+        assert!(!KmRefresher::should_announce(false, 0, 1 << 25, 1 << 20));
+    }
+
+    #[test]
+    fn due_once_inside_the_pre_announce_margin() {
+        let period = 1 << 25;
+        let margin = 1 << 20;
+        assert!(KmRefresher::should_announce(false, period - margin, period, margin));
+    }
+
+    #[test]
+    fn not_due_again_while_a_refresh_is_already_pending() {
+        let period = 1 << 25;
+        let margin = 1 << 20;
+        assert!(!KmRefresher::should_announce(true, period, period, margin));
+    }
+}
+
 pub enum GenHsv5Result {
     Accept(HandshakeVSInfo, ConnectionSettings),
     NotHandled(ConnectError),
     Reject(ConnectionReject),
 }
 
+/// How a listener treats peers offering the legacy HSv4 (UDT-style)
+/// handshake instead of HSv5. A single handshake only ever offers one
+/// version, so there's no "prefer v4 over an available v5" case to model -
+/// either v4 is accepted as a fallback or it isn't.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hsv4Policy {
+    /// Only accept HSv5 peers.
+    Forbid,
+    /// Accept HSv4 peers as well as HSv5 ones.
+    Allow,
+}
+
+impl Hsv4Policy {
+    fn accepts_hsv4(self) -> bool {
+        self != Hsv4Policy::Forbid
+    }
+}
+
+#[cfg(test)]
+mod hsv4_policy_tests {
+    use super::*;
+
+    #[test]
+    fn forbid_rejects_hsv4() {
+        assert!(!Hsv4Policy::Forbid.accepts_hsv4());
+    }
+
+    #[test]
+    fn allow_accepts_hsv4() {
+        assert!(Hsv4Policy::Allow.accepts_hsv4());
+    }
+}
+
 pub fn gen_hsv5_response(
     settings: &mut ConnInitSettings,
     with_hsv5: &HandshakeControlInfo,
     from: SocketAddr,
     acceptor: &mut impl StreamAcceptor,
+    groups: &mut GroupManager,
+) -> GenHsv5Result {
+    match &with_hsv5.info {
+        HandshakeVSInfo::V5(_) => {
+            gen_hsv5_response_v5(settings, with_hsv5, from, acceptor, groups)
+        }
+        HandshakeVSInfo::V4(_) if settings.hsv4_policy.accepts_hsv4() => {
+            gen_hsv4_response(settings, with_hsv5, from, acceptor)
+        }
+        _ => GenHsv5Result::Reject(ConnectionReject::Rejecting(
+            ServerRejectReason::Version.into(),
+        )),
+    }
+}
+
+/// Packet-size and flow-window limits negotiated between two sides'
+/// proposals: each converges on whichever side asked for less, so the peer
+/// can echo the value back in its own conclusion and both ends agree
+/// without a further round trip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct NegotiatedLimits {
+    max_packet_size: u32,
+    max_flow_size: u32,
+}
+
+fn negotiate_limits(
+    local_mss: u32,
+    local_flow: u32,
+    peer_mss: u32,
+    peer_flow: u32,
+) -> NegotiatedLimits {
+    NegotiatedLimits {
+        max_packet_size: local_mss.min(peer_mss),
+        max_flow_size: local_flow.min(peer_flow),
+    }
+}
+
+#[cfg(test)]
+mod negotiate_limits_tests {
+    use super::*;
+
+    #[test]
+    fn takes_the_smaller_of_each_proposal() {
+        assert_eq!(
+            negotiate_limits(1500, 8192, 1400, 16384),
+            NegotiatedLimits {
+                max_packet_size: 1400,
+                max_flow_size: 8192,
+            }
+        );
+    }
+
+    #[test]
+    fn matching_proposals_are_unchanged() {
+        assert_eq!(
+            negotiate_limits(1500, 8192, 1500, 8192),
+            NegotiatedLimits {
+                max_packet_size: 1500,
+                max_flow_size: 8192,
+            }
+        );
+    }
+}
+
+/// Accepts a legacy HSv4 peer. Latency and crypto for HSv4 aren't carried
+/// inline in the conclusion packet the way they are for HSv5: latency
+/// arrives in a separate SRT extension control packet sent right after the
+/// handshake completes, and crypto (if any) arrives via the ordinary KM
+/// exchange. This function handles neither follow-up packet: latency is
+/// taken from the local settings and no crypto manager is set up. HSv4
+/// predates StreamID and group bonding, so neither applies.
+fn gen_hsv4_response(
+    settings: &mut ConnInitSettings,
+    with_hsv5: &HandshakeControlInfo,
+    from: SocketAddr,
+    acceptor: &mut impl StreamAcceptor,
+) -> GenHsv5Result {
+    let mut accept_params = match acceptor.accept(None, None, from) {
+        Ok(ap) => ap,
+        Err(rr) => return GenHsv5Result::Reject(ConnectionReject::Rejecting(rr)),
+    };
+
+    if let Some(co) = accept_params.take_crypto_options() {
+        settings.crypto = Some(co);
+    }
+
+    let limits = negotiate_limits(
+        settings.mss,
+        settings.max_flow_size,
+        with_hsv5.max_packet_size,
+        with_hsv5.max_flow_size,
+    );
+
+    GenHsv5Result::Accept(
+        HandshakeVSInfo::V4(SrtShakeFlags::SUPPORTED),
+        ConnectionSettings {
+            remote: from,
+            remote_sockid: with_hsv5.socket_id,
+            local_sockid: settings.local_sockid,
+            socket_start_time: Instant::now(),
+            init_send_seq_num: settings.starting_send_seqnum,
+            init_recv_seq_num: with_hsv5.init_seq_num,
+            group_id: None,
+            max_packet_size: limits.max_packet_size,
+            max_flow_size: limits.max_flow_size,
+            send_tsbpd_latency: settings.send_latency,
+            recv_tsbpd_latency: settings.recv_latency,
+            crypto_manager: None,
+            stream_id: None,
+        },
+    )
+}
+
+/// The shape of an incoming KM extension, independent of its payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KmExtKind {
+    Request,
+    Other,
+    Absent,
+}
+
+impl KmExtKind {
+    fn of(ext_km: &Option<SrtControlPacket>) -> Self {
+        match ext_km {
+            Some(SrtControlPacket::KeyManagerRequest(_)) => KmExtKind::Request,
+            Some(_) => KmExtKind::Other,
+            None => KmExtKind::Absent,
+        }
+    }
+}
+
+/// How the local crypto config and the peer's KM extension line up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CryptoOutcome {
+    Negotiate,
+    NoCrypto,
+    UnexpectedKm,
+    Asymmetric,
+}
+
+fn classify_crypto(have_local_crypto: bool, peer_km: KmExtKind) -> CryptoOutcome {
+    match (have_local_crypto, peer_km) {
+        (true, KmExtKind::Request) => CryptoOutcome::Negotiate,
+        (false, KmExtKind::Absent) => CryptoOutcome::NoCrypto,
+        (true, KmExtKind::Other) => CryptoOutcome::UnexpectedKm,
+        (true, KmExtKind::Absent) | (false, KmExtKind::Request) | (false, KmExtKind::Other) => {
+            CryptoOutcome::Asymmetric
+        }
+    }
+}
+
+#[cfg(test)]
+mod crypto_classification_tests {
+    use super::*;
+
+    #[test]
+    fn both_configured_negotiates() {
+        assert_eq!(
+            classify_crypto(true, KmExtKind::Request),
+            CryptoOutcome::Negotiate
+        );
+    }
+
+    #[test]
+    fn neither_configured_is_fine() {
+        assert_eq!(
+            classify_crypto(false, KmExtKind::Absent),
+            CryptoOutcome::NoCrypto
+        );
+    }
+
+    #[test]
+    fn local_crypto_with_non_request_km_is_unexpected() {
+        assert_eq!(
+            classify_crypto(true, KmExtKind::Other),
+            CryptoOutcome::UnexpectedKm
+        );
+    }
+
+    #[test]
+    fn only_local_configured_is_asymmetric() {
+        assert_eq!(
+            classify_crypto(true, KmExtKind::Absent),
+            CryptoOutcome::Asymmetric
+        );
+    }
+
+    #[test]
+    fn only_peer_configured_is_asymmetric() {
+        assert_eq!(
+            classify_crypto(false, KmExtKind::Request),
+            CryptoOutcome::Asymmetric
+        );
+        assert_eq!(
+            classify_crypto(false, KmExtKind::Other),
+            CryptoOutcome::Asymmetric
+        );
+    }
+}
+
+fn gen_hsv5_response_v5(
+    settings: &mut ConnInitSettings,
+    with_hsv5: &HandshakeControlInfo,
+    from: SocketAddr,
+    acceptor: &mut impl StreamAcceptor,
+    groups: &mut GroupManager,
 ) -> GenHsv5Result {
     let incoming = match &with_hsv5.info {
         HandshakeVSInfo::V5(hs) => hs,
-        _ => {
+        _ => unreachable!("gen_hsv5_response_v5 called with a non-v5 handshake"),
+    };
+
+    let parsed_sid = match incoming.sid.as_deref().map(StreamId::parse).transpose() {
+        Ok(sid) => sid.flatten(),
+        Err(_) => {
             return GenHsv5Result::Reject(ConnectionReject::Rejecting(
-                ServerRejectReason::Version.into(), // TODO: this error is tehcnially reserved for access control handlers, as the ref impl supports hsv4+5, while we only support 5
+                ServerRejectReason::BadRequest.into(),
             ));
         }
     };
 
-    let mut accept_params = match acceptor.accept(incoming.sid.as_deref(), from) {
-        Ok(ap) => ap,
-        Err(rr) => return GenHsv5Result::Reject(ConnectionReject::Rejecting(rr)),
-    };
+    let mut accept_params =
+        match acceptor.accept(incoming.sid.as_deref(), parsed_sid.as_ref(), from) {
+            Ok(ap) => ap,
+            Err(rr) => return GenHsv5Result::Reject(ConnectionReject::Rejecting(rr)),
+        };
 
     // apply parameters generated by acceptor
     if let Some(co) = accept_params.take_crypto_options() {
@@ -52,24 +387,42 @@ pub fn gen_hsv5_response(
         None => return GenHsv5Result::NotHandled(ConnectError::ExpectedExtFlags),
     };
 
-    // crypto
-    let cm = match (&settings.crypto, &incoming.ext_km) {
-        // ok, both sizes have crypto
-        (Some(co), Some(SrtControlPacket::KeyManagerRequest(km))) => {
-            if co.size != incoming.crypto_size {
-                unimplemented!("Key size mismatch");
-            }
-
-            Some(match CryptoManager::new_from_kmreq(co.clone(), km) {
-                Ok(cm) => cm,
-                Err(rr) => return GenHsv5Result::Reject(rr),
-            })
+    // crypto: the key length is negotiated, not assumed fixed - see
+    // `CryptoManager::new_from_kmreq`.
+    let cm = match classify_crypto(settings.crypto.is_some(), KmExtKind::of(&incoming.ext_km)) {
+        CryptoOutcome::Negotiate => {
+            let (co, km) = match (&settings.crypto, &incoming.ext_km) {
+                (Some(co), Some(SrtControlPacket::KeyManagerRequest(km))) => (co, km),
+                _ => unreachable!("classify_crypto returned Negotiate"),
+            };
+            Some(
+                match CryptoManager::new_from_kmreq(
+                    co.clone(),
+                    incoming.crypto_size,
+                    km,
+                    settings.km_refresh_period,
+                    settings.km_pre_announce,
+                ) {
+                    Ok(cm) => cm,
+                    Err(rr) => return GenHsv5Result::Reject(rr),
+                },
+            )
+        }
+        CryptoOutcome::NoCrypto => None,
+        // the peer sent a KM extension that wasn't a request (e.g. echoed
+        // its own response) - nothing we can negotiate against.
+        CryptoOutcome::UnexpectedKm => {
+            return GenHsv5Result::Reject(ConnectionReject::Rejecting(
+                ServerRejectReason::BadRequest.into(),
+            ));
+        }
+        // one side configured a passphrase and the other didn't: a
+        // diagnosable rejection rather than an unreachable panic.
+        CryptoOutcome::Asymmetric => {
+            return GenHsv5Result::Reject(ConnectionReject::Rejecting(
+                CoreRejectReason::Unsecured.into(),
+            ));
         }
-        // ok, neither have crypto
-        (None, None) => None,
-        // bad cases
-        (Some(_), Some(_)) => unimplemented!("Expected kmreq"),
-        (Some(_), None) | (None, Some(_)) => unimplemented!("Crypto mismatch"),
     };
     let outgoing_ext_km = if let Some(cm) = &cm {
         Some(cm.generate_km())
@@ -82,6 +435,36 @@ pub fn gen_hsv5_response(
         None
     };
 
+    // group bonding: join an existing group keyed by group id, or allocate one.
+    let (group_id, outgoing_ext_group) = match &incoming.ext_group {
+        Some(SrtControlPacket::GroupRequest(req)) => {
+            let member = GroupMember {
+                remote: from,
+                remote_sockid: with_hsv5.socket_id,
+                weight: req.weight,
+                health: MemberHealth::Active,
+            };
+            let group_type = groups.join_or_create(req.group_id, req.group_type, member);
+            (
+                Some(req.group_id),
+                Some(SrtControlPacket::GroupResponse(GroupResponse {
+                    group_id: req.group_id,
+                    group_type,
+                    flags: req.flags,
+                })),
+            )
+        }
+        Some(_) => return GenHsv5Result::NotHandled(ConnectError::ExpectedGroupReq),
+        None => (None, None),
+    };
+
+    let limits = negotiate_limits(
+        settings.mss,
+        settings.max_flow_size,
+        with_hsv5.max_packet_size,
+        with_hsv5.max_flow_size,
+    );
+
     GenHsv5Result::Accept(
         HandshakeVSInfo::V5(HSV5Info {
             crypto_size: cm.as_ref().map(|c| c.key_length()).unwrap_or(0),
@@ -92,6 +475,7 @@ pub fn gen_hsv5_response(
                 recv_latency: settings.recv_latency,
             })),
             ext_km: outgoing_ext_km.map(SrtControlPacket::KeyManagerResponse),
+            ext_group: outgoing_ext_group,
             sid,
         }),
         ConnectionSettings {
@@ -101,8 +485,9 @@ pub fn gen_hsv5_response(
             socket_start_time: Instant::now(), // xxx?
             init_send_seq_num: settings.starting_send_seqnum,
             init_recv_seq_num: with_hsv5.init_seq_num,
-            max_packet_size: 1500, // todo: parameters!
-            max_flow_size: 8192,
+            group_id,
+            max_packet_size: limits.max_packet_size,
+            max_flow_size: limits.max_flow_size,
             send_tsbpd_latency: Duration::max(settings.send_latency, hs.recv_latency),
             recv_tsbpd_latency: Duration::max(settings.recv_latency, hs.send_latency),
             crypto_manager: cm,
@@ -116,6 +501,7 @@ pub struct StartedInitiator {
     cm: Option<CryptoManager>,
     settings: ConnInitSettings,
     streamid: Option<String>,
+    group_id: Option<GroupId>,
 }
 
 pub fn start_hsv5_initiation(
@@ -124,18 +510,31 @@ pub fn start_hsv5_initiation(
 ) -> (HandshakeVSInfo, StartedInitiator) {
     let self_crypto_size = settings.crypto.as_ref().map(|co| co.size).unwrap_or(0);
 
-    // if peer_crypto_size != self_crypto_size {
-    //     unimplemented!("Unimplemted crypto mismatch!");
-    // }
-
     let (cm, ext_km) = if let Some(co) = &settings.crypto {
-        let cm = CryptoManager::new_random(co.clone());
+        let cm = CryptoManager::new_random(
+            co.clone(),
+            settings.km_refresh_period,
+            settings.km_pre_announce,
+        );
         let kmreq = SrtControlPacket::KeyManagerRequest(cm.generate_km());
         (Some(cm), Some(kmreq))
     } else {
         (None, None)
     };
 
+    let (group_id, ext_group) = match &settings.group {
+        Some(group) => (
+            Some(group.id),
+            Some(SrtControlPacket::GroupRequest(GroupRequest {
+                group_id: group.id,
+                group_type: group.group_type,
+                flags: 0,
+                weight: group.weight,
+            })),
+        ),
+        None => (None, None),
+    };
+
     (
         HandshakeVSInfo::V5(HSV5Info {
             crypto_size: self_crypto_size,
@@ -146,25 +545,67 @@ pub fn start_hsv5_initiation(
                 recv_latency: settings.recv_latency,
             })),
             ext_km,
+            ext_group,
             sid: streamid.clone(),
         }),
         StartedInitiator {
             cm,
             settings,
             streamid,
+            group_id,
         },
     )
 }
 
 impl StartedInitiator {
+    /// If the peer rejected our HSv5 conclusion with a version error and
+    /// HSv4 fallback isn't forbidden, builds the HSv4 conclusion to retry
+    /// the connection with.
+    pub fn retry_as_hsv4(&self) -> Option<HandshakeVSInfo> {
+        if !self.settings.hsv4_policy.accepts_hsv4() {
+            return None;
+        }
+        Some(HandshakeVSInfo::V4(SrtShakeFlags::SUPPORTED))
+    }
+
     pub fn finish_hsv5_initiation(
         self,
         response: &HandshakeControlInfo,
         from: SocketAddr,
     ) -> Result<ConnectionSettings, ConnectError> {
-        // TODO: factor this out with above...
         let incoming = match &response.info {
             HandshakeVSInfo::V5(hs) => hs,
+            HandshakeVSInfo::V4(_) if self.settings.hsv4_policy.accepts_hsv4() => {
+                // HSv4 carries no inline HS/KM/sid extensions; latency and
+                // crypto arrive via follow-up packets, same as the listener
+                // side in `gen_hsv4_response`.
+                let limits = negotiate_limits(
+                    self.settings.mss,
+                    self.settings.max_flow_size,
+                    response.max_packet_size,
+                    response.max_flow_size,
+                );
+                return Ok(ConnectionSettings {
+                    remote: from,
+                    remote_sockid: response.socket_id,
+                    local_sockid: self.settings.local_sockid,
+                    socket_start_time: Instant::now(),
+                    init_send_seq_num: self.settings.starting_send_seqnum,
+                    init_recv_seq_num: response.init_seq_num,
+                    group_id: None,
+                    max_packet_size: limits.max_packet_size,
+                    max_flow_size: limits.max_flow_size,
+                    send_tsbpd_latency: self.settings.send_latency,
+                    recv_tsbpd_latency: self.settings.recv_latency,
+                    // `self.cm`'s key was generated for the rejected v5
+                    // conclusion and was never delivered to the peer over
+                    // any channel, so it isn't used: the connection has no
+                    // crypto manager, same as the listener side in
+                    // `gen_hsv4_response`.
+                    crypto_manager: None,
+                    stream_id: self.streamid,
+                });
+            }
             i => return Err(ConnectError::UnsupportedProtocolVersion(i.version())),
         };
 
@@ -174,7 +615,31 @@ impl StartedInitiator {
             None => return Err(ConnectError::ExpectedExtFlags),
         };
 
-        // todo: validate km!
+        // the responder echoes back the (possibly reassigned) group id and
+        // type once it has joined or created the group.
+        let group_id = match (self.group_id, &incoming.ext_group) {
+            (Some(_), Some(SrtControlPacket::GroupResponse(resp))) => Some(resp.group_id),
+            (Some(_), _) => return Err(ConnectError::ExpectedExtFlags),
+            (None, _) => None,
+        };
+
+        // validate the KMRSP against the KMREQ we sent: confirms the peer
+        // installed the same key and, on a rekey, that it landed in the
+        // spare slot rather than clobbering the key still in flight.
+        match (&self.cm, &incoming.ext_km) {
+            (Some(cm), Some(SrtControlPacket::KeyManagerResponse(kmrsp))) => {
+                cm.validate_km(kmrsp)?;
+            }
+            (Some(_), _) => return Err(ConnectError::ExpectedKMResp),
+            (None, _) => {}
+        }
+
+        let limits = negotiate_limits(
+            self.settings.mss,
+            self.settings.max_flow_size,
+            response.max_packet_size,
+            response.max_flow_size,
+        );
 
         // validate response
         Ok(ConnectionSettings {
@@ -184,8 +649,9 @@ impl StartedInitiator {
             socket_start_time: Instant::now(), // xxx?
             init_send_seq_num: self.settings.starting_send_seqnum,
             init_recv_seq_num: response.init_seq_num,
-            max_packet_size: 1500, // todo: parameters!
-            max_flow_size: 8192,
+            group_id,
+            max_packet_size: limits.max_packet_size,
+            max_flow_size: limits.max_flow_size,
             send_tsbpd_latency: Duration::max(self.settings.send_latency, hs.recv_latency),
             recv_tsbpd_latency: Duration::max(self.settings.recv_latency, hs.send_latency),
             crypto_manager: self.cm,
@@ -0,0 +1,180 @@
+//! Socket-group (bonding) membership, tracked across the handshake and the
+//! life of a connection.
+
+use crate::packet::{GroupId, GroupType};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+};
+
+/// Whether a group member's link is still carrying traffic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemberHealth {
+    Active,
+    Failed,
+}
+
+/// A single member link within a socket group.
+#[derive(Debug, Clone)]
+pub struct GroupMember {
+    pub remote: SocketAddr,
+    pub remote_sockid: crate::SocketId,
+    pub weight: u16,
+    pub health: MemberHealth,
+}
+
+/// Tracks the members of socket groups this side has accepted links for, so
+/// a sender can fan packets out to the active ones and a receiver can dedup
+/// by sequence number across them (see [`GroupDedup`]). Wiring `active_members`
+/// into the actual send loop, and `GroupDedup` into the actual receive loop,
+/// is left to the caller - those loops live outside the handshake.
+#[derive(Debug, Clone, Default)]
+pub struct GroupManager {
+    groups: HashMap<GroupId, (GroupType, Vec<GroupMember>)>,
+}
+
+impl GroupManager {
+    pub fn new() -> Self {
+        GroupManager::default()
+    }
+
+    /// Joins `id` if it exists (returning its type), or allocates a new
+    /// group otherwise. Matches on the peer, so a reconnecting link updates
+    /// in place instead of leaving a stale duplicate.
+    pub fn join_or_create(
+        &mut self,
+        id: GroupId,
+        group_type: GroupType,
+        member: GroupMember,
+    ) -> GroupType {
+        let (existing_type, members) = self
+            .groups
+            .entry(id)
+            .or_insert_with(|| (group_type, Vec::new()));
+        match members.iter_mut().find(|m| m.remote == member.remote) {
+            Some(existing) => {
+                existing.remote_sockid = member.remote_sockid;
+                existing.weight = member.weight;
+                existing.health = MemberHealth::Active;
+            }
+            None => members.push(member),
+        }
+        *existing_type
+    }
+
+    /// Marks a member's link failed, e.g. on a send timeout, so senders
+    /// stop replicating to it while a later reconnect can still revive it.
+    pub fn mark_failed(&mut self, id: GroupId, remote: SocketAddr) {
+        if let Some((_, members)) = self.groups.get_mut(&id) {
+            if let Some(m) = members.iter_mut().find(|m| m.remote == remote) {
+                m.health = MemberHealth::Failed;
+            }
+        }
+    }
+
+    /// Drops a member entirely, once its failure is confirmed permanent
+    /// rather than a transient drop.
+    pub fn remove(&mut self, id: GroupId, remote: SocketAddr) {
+        if let Some((_, members)) = self.groups.get_mut(&id) {
+            members.retain(|m| m.remote != remote);
+        }
+    }
+
+    /// The members a sender should currently replicate to.
+    pub fn active_members(&self, id: GroupId) -> impl Iterator<Item = &GroupMember> {
+        self.groups
+            .get(&id)
+            .into_iter()
+            .flat_map(|(_, members)| members.iter())
+            .filter(|m| m.health == MemberHealth::Active)
+    }
+}
+
+/// Dedups packets received redundantly across a group's member links by
+/// sequence number.
+#[derive(Debug, Clone, Default)]
+pub struct GroupDedup {
+    seen: HashSet<i32>,
+}
+
+impl GroupDedup {
+    pub fn new() -> Self {
+        GroupDedup::default()
+    }
+
+    /// Returns `true` if `seq` was already delivered on another member link
+    /// (and should be dropped), recording it as seen either way.
+    pub fn is_duplicate(&mut self, seq: i32) -> bool {
+        !self.seen.insert(seq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port))
+    }
+
+    fn member(port: u16, weight: u16) -> GroupMember {
+        GroupMember {
+            remote: addr(port),
+            remote_sockid: Default::default(),
+            weight,
+            health: MemberHealth::Active,
+        }
+    }
+
+    #[test]
+    fn creates_a_new_group_on_first_join() {
+        let mut groups = GroupManager::new();
+        let ty = groups.join_or_create(GroupId(1), GroupType::Backup, member(9000, 1));
+        assert_eq!(ty, GroupType::Backup);
+        assert_eq!(groups.active_members(GroupId(1)).count(), 1);
+    }
+
+    #[test]
+    fn rejoin_by_the_same_peer_updates_in_place() {
+        let mut groups = GroupManager::new();
+        groups.join_or_create(GroupId(1), GroupType::Backup, member(9000, 1));
+        groups.join_or_create(GroupId(1), GroupType::Backup, member(9000, 5));
+        let members: Vec<_> = groups.active_members(GroupId(1)).collect();
+        assert_eq!(members.len(), 1);
+        assert_eq!(members[0].weight, 5);
+    }
+
+    #[test]
+    fn failed_members_are_excluded_from_active_members() {
+        let mut groups = GroupManager::new();
+        groups.join_or_create(GroupId(1), GroupType::Backup, member(9000, 1));
+        groups.mark_failed(GroupId(1), addr(9000));
+        assert_eq!(groups.active_members(GroupId(1)).count(), 0);
+    }
+
+    #[test]
+    fn reconnect_after_failure_is_active_again() {
+        let mut groups = GroupManager::new();
+        groups.join_or_create(GroupId(1), GroupType::Backup, member(9000, 1));
+        groups.mark_failed(GroupId(1), addr(9000));
+        groups.join_or_create(GroupId(1), GroupType::Backup, member(9000, 1));
+        assert_eq!(groups.active_members(GroupId(1)).count(), 1);
+    }
+
+    #[test]
+    fn removed_members_are_gone_for_good() {
+        let mut groups = GroupManager::new();
+        groups.join_or_create(GroupId(1), GroupType::Backup, member(9000, 1));
+        groups.remove(GroupId(1), addr(9000));
+        assert_eq!(groups.active_members(GroupId(1)).count(), 0);
+    }
+
+    #[test]
+    fn dedup_flags_repeats_but_not_first_sight() {
+        let mut dedup = GroupDedup::new();
+        assert!(!dedup.is_duplicate(42));
+        assert!(dedup.is_duplicate(42));
+        assert!(!dedup.is_duplicate(43));
+    }
+}